@@ -1,7 +1,7 @@
 #![deny(warnings, clippy::all)]
 
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     path::{Path, PathBuf},
     process::Command,
 };
@@ -10,6 +10,18 @@ struct Vars {
     cwd: PathBuf,
     target_dir: PathBuf,
     verbose: bool,
+    /// Build/test in release profile (`--release`) instead of debug.
+    release: bool,
+    /// Arguments collected after a trailing `--`, forwarded to the underlying
+    /// `cargo test`/`cargo clippy` invocations.
+    extra_args: Vec<String>,
+    /// Use `cargo nextest` to run tests instead of `cargo test`. Set either by
+    /// `--nextest` or by auto-detecting that nextest is installed.
+    nextest: bool,
+    /// Values of the per-task flags declared in [`ACTIONS`] and parsed off the
+    /// command line, keyed by flag name (without the leading `--`). Value flags
+    /// store `Some(value)`; bare switches store `None`.
+    task_flags: BTreeMap<String, Option<String>>,
 }
 
 impl Vars {
@@ -20,6 +32,29 @@ impl Vars {
             &[]
         }
     }
+
+    fn release_arg(&self) -> &'static [&'static str] {
+        if self.release {
+            &["--release"]
+        } else {
+            &[]
+        }
+    }
+
+    /// The value of a per-task value flag, if it was supplied on the command
+    /// line.
+    fn flag(&self, name: &str) -> Option<&str> {
+        self.task_flags.get(name).and_then(|v| v.as_deref())
+    }
+}
+
+/// A flag a task declares for itself, parsed only when that task is named on
+/// the command line. `takes_value` distinguishes `--format lcov` from a bare
+/// switch.
+struct TaskFlag {
+    name: &'static str,
+    takes_value: bool,
+    help: &'static str,
 }
 
 enum Action<'a> {
@@ -30,9 +65,19 @@ enum Action<'a> {
 
 use Action::*;
 
-static ACTIONS: &[(&str, &[Action])] = &[
+/// A coverage task's choice of output format, declared once and shared by the
+/// coverage entries below.
+static FORMAT_FLAG: &[TaskFlag] = &[TaskFlag {
+    name: "format",
+    takes_value: true,
+    help: "Coverage output format (lcov, text or html)",
+}];
+
+static ACTIONS: &[(&str, &str, &[TaskFlag], &[Action])] = &[
     (
         "before-pr",
+        "Run the full pre-PR pipeline: lints, builds, tests and checks",
+        &[],
         &[
             Cargo("update", &[]),
             Run("lints"),
@@ -43,6 +88,8 @@ static ACTIONS: &[(&str, &[Action])] = &[
     ),
     (
         "all-checks",
+        "Run the dependency and semver auditing checks",
+        &[],
         &[
             Cargo("deny", &["check"]),
             Cargo("semver-checks", &[]),
@@ -54,134 +101,785 @@ static ACTIONS: &[(&str, &[Action])] = &[
     ),
     (
         "lints",
+        "Check formatting and run clippy",
+        &[],
         &[
             Cargo("fmt", &["--check"]),
             Cargo("clippy", &["--workspace"]),
         ],
     ),
+    (
+        "fix",
+        "Apply machine-applicable clippy/rustc fixes, then format and re-check",
+        &[],
+        &[
+            Cargo(
+                "clippy",
+                &["--workspace", "--fix", "--allow-dirty", "--allow-staged"],
+            ),
+            Cargo("fix", &["--workspace", "--allow-dirty", "--allow-staged"]),
+            Cargo("fmt", &[]),
+            // Gate the final check with `-D warnings` so `fix` actually verifies
+            // a clean tree rather than merely reporting any stragglers.
+            Cargo("clippy", &["--workspace", "--", "-D", "warnings"]),
+        ],
+    ),
     (
         "build-all",
+        "Build the workspace in debug, test and release profiles",
+        &[],
         &[
             Cargo("build", &["--workspace"]),
             Cargo("build", &["--workspace", "--tests"]),
             Cargo("build", &["--workspace", "--release"]),
         ],
     ),
-    ("all-tests", &[Cargo("test", &["--workspace"])]),
-    ("lcov-coverage", &[Call(&run_lcov_coverage)]),
-    ("html-coverage", &[Call(&run_html_coverage)]),
+    (
+        "all-tests",
+        "Run the whole test suite",
+        &[],
+        &[Call(&run_all_tests)],
+    ),
+    (
+        "lcov-coverage",
+        "Generate an lcov coverage report",
+        FORMAT_FLAG,
+        &[Call(&run_lcov_coverage)],
+    ),
+    (
+        "html-coverage",
+        "Generate an HTML coverage report",
+        FORMAT_FLAG,
+        &[Call(&run_html_coverage)],
+    ),
+    (
+        "diff-coverage",
+        "Fail when changed lines since a base ref aren't covered (-- <base> <threshold>)",
+        &[],
+        &[Call(&run_diff_coverage)],
+    ),
 ];
 
-fn grcov(target_dir: &Path, format: &str, build_type: &str) {
-    let ret = Command::new("grcov")
-        .args(["."])
-        .args([
-            "--binary-path",
-            target_dir
-                .join(format!("{build_type}/deps"))
-                .to_str()
-                .unwrap(),
-        ])
-        .args(["-s", "."])
-        .args(["-t", format])
-        .args(["--branch"])
-        .args(["--ignore-not-existing"])
-        .args(["-o", target_dir.join(format).to_str().unwrap()])
-        .args(["--keep-only", "src/*"])
-        .args(["--keep-only", "derive/src/*"])
+/// The per-task flags declared by `name`, or an empty slice for config-file
+/// tasks and unknown names.
+fn task_flags(name: &str) -> &'static [TaskFlag] {
+    ACTIONS
+        .iter()
+        .find(|(t, ..)| *t == name)
+        .map(|(_, _, flags, _)| *flags)
+        .unwrap_or(&[])
+}
+
+/// Locate the directory holding the `llvm-tools-preview` binaries that ship
+/// with the active toolchain.
+///
+/// `rustc --print target-libdir` points at `.../rustlib/<triple>/lib`; the
+/// `llvm-profdata`/`llvm-cov` helpers live in the sibling `bin` directory, so
+/// we don't depend on a system-wide LLVM install being on `PATH`.
+fn llvm_tools_dir() -> PathBuf {
+    let out = Command::new("rustc")
+        .args(["--print", "target-libdir"])
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to run rustc: {e}"));
+    assert!(out.status.success(), "rustc --print target-libdir failed");
+    let libdir = PathBuf::from(String::from_utf8(out.stdout).unwrap().trim());
+    libdir
+        .parent()
+        .unwrap_or_else(|| panic!("Unexpected target-libdir {libdir:?}"))
+        .join("bin")
+}
+
+/// Full path to one of the bundled LLVM tools (e.g. `llvm-profdata`), verifying
+/// it is actually present so we can point at the `llvm-tools-preview` component.
+fn llvm_tool(tools_dir: &Path, name: &str) -> PathBuf {
+    let exe = tools_dir.join(name);
+    assert!(
+        exe.is_file(),
+        "Missing {name}, perhaps you need to run 'rustup component add llvm-tools-preview'"
+    );
+    exe
+}
+
+/// Build the workspace test binaries with coverage instrumentation and return
+/// the paths of the resulting executables.
+///
+/// The binaries are what `llvm-cov` needs as `-object` arguments; we discover
+/// them from the JSON `compiler-artifact` messages, keeping only the artifacts
+/// that report an `executable`.
+fn build_instrumented_tests(vars: &Vars, target_dir: &Path, features: &[&str]) -> Vec<PathBuf> {
+    let out = Command::new("cargo")
+        .env("CARGO_TARGET_DIR", target_dir)
+        .env("CARGO_INCREMENTAL", "0")
+        .env("RUSTFLAGS", "-Cinstrument-coverage")
+        .arg("test")
+        .arg("--workspace")
+        .arg("--no-run")
+        .arg("--message-format=json")
+        .args(features)
+        .args(vars.release_arg())
+        .args(vars.verbose_arg())
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to run cargo: {e}"));
+    assert!(out.status.success(), "cargo test --no-run failed");
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let mut binaries = Vec::new();
+    for line in stdout.lines() {
+        // Only the test artifacts carry a non-null `executable`; bins without
+        // tests report `"executable":null`.
+        if let Some(exe) = json_field(line, "executable") {
+            binaries.push(PathBuf::from(exe));
+        }
+    }
+    assert!(!binaries.is_empty(), "No instrumented test binaries were built");
+    binaries
+}
+
+/// Recursively collect the test/doctest executables under any `deps` directory
+/// of `target_dir`.
+///
+/// Compiled artifacts carry an extension (`.rlib`, `.rmeta`, `.d`); the runnable
+/// test and persisted-doctest binaries do not, so an extensionless file inside a
+/// `deps` directory is the one we want as an `-object`.
+fn glob_test_binaries(target_dir: &Path) -> Vec<PathBuf> {
+    fn walk(dir: &Path, in_deps: bool, out: &mut Vec<PathBuf>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                let child_in_deps = in_deps || path.file_name() == Some("deps".as_ref());
+                walk(&path, child_in_deps, out);
+            } else if in_deps && path.extension().is_none() {
+                out.push(path);
+            }
+        }
+    }
+    let mut out = Vec::new();
+    walk(target_dir, false, &mut out);
+    out
+}
+
+/// Extract the string value of a top-level `"<key>":"<value>"` field from a
+/// single line of `--message-format=json` output.
+///
+/// Returns `None` when the key is absent or its value is `null`, which is all
+/// we need to distinguish runnable test executables from library artifacts.
+fn json_field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let needle = format!("\"{key}\":");
+    let rest = &line[line.find(needle.as_str())? + needle.len()..];
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix('"')?;
+    Some(&rest[..rest.find('"')?])
+}
+
+/// Whether `cargo nextest` can be invoked, used to auto-enable the nextest
+/// backend when the subcommand is installed.
+fn nextest_available() -> bool {
+    Command::new("cargo")
+        .args(["nextest", "--version"])
+        .output()
+        .map(|out| out.status.success())
+        .unwrap_or(false)
+}
+
+/// Whether the optional `rustfilt` binary is on `PATH`.
+///
+/// `rustfilt` is a separate `cargo install rustfilt`, not part of the
+/// `llvm-tools-preview` component, so we only point `llvm-cov` at it as a
+/// demangler when it is actually available and otherwise fall back to
+/// `llvm-cov`'s own built-in demangling.
+fn rustfilt_available() -> bool {
+    Command::new("rustfilt")
+        .arg("-h")
+        .output()
+        .map(|out| out.status.success())
+        .unwrap_or(false)
+}
+
+/// Run the workspace test suite, preferring `cargo nextest` when enabled.
+///
+/// nextest does not execute doctests, so those are run with a separate
+/// `cargo test --doc` step to keep test completeness.
+fn run_all_tests(vars: &Vars) {
+    // Route through `cargo_cmd` (not `run_cargo`) so the trailing `-- <args>`
+    // collected by the CLI still reach `cargo test`/`cargo nextest run`, the
+    // subcommands the passthrough exists for. `cargo_cmd` adds no profile flag,
+    // so `--release` is appended here.
+    let release = vars.release_arg();
+    if vars.nextest {
+        cargo_cmd("nextest", &[&["run", "--workspace"][..], release].concat(), vars);
+        cargo_cmd("test", &[&["--doc", "--workspace"][..], release].concat(), vars);
+    } else {
+        cargo_cmd("test", &[&["--workspace"][..], release].concat(), vars);
+    }
+}
+
+/// Run `cargo <command> <args>` with the given pre-seeded `Command` (used to
+/// carry coverage environment variables), appending the verbose/release flags.
+fn run_cargo(command: &str, args: &[&str], vars: &Vars, cmd: &mut Command) {
+    let ret = cmd
+        .arg(command)
+        .args(args)
+        .args(vars.release_arg())
+        .args(vars.verbose_arg())
         .status()
-        .expect("Perhaps you need to run 'cargo install grcov'")
+        .unwrap_or_else(|e| panic!("Failed to run cargo: {e}"))
         .success();
     assert!(ret);
 }
 
+/// A `cargo` command pre-seeded with the source-based coverage environment so
+/// every invocation writes its `*.profraw` files into `profraw_dir`.
+fn instrumented_cargo(target_dir: &Path, profraw_dir: &Path) -> Command {
+    let mut cmd = Command::new("cargo");
+    cmd.env("CARGO_TARGET_DIR", target_dir)
+        .env("CARGO_INCREMENTAL", "0")
+        .env("RUSTFLAGS", "-Cinstrument-coverage")
+        .env("LLVM_PROFILE_FILE", profraw_dir.join("cov-%p-%8m.profraw"));
+    cmd
+}
+
 fn run_lcov_coverage(vars: &Vars) {
-    code_coverage(vars, "lcov")
+    code_coverage(vars, vars.flag("format").unwrap_or("lcov"))
 }
 
 fn run_html_coverage(vars: &Vars) {
-    code_coverage(vars, "html")
+    code_coverage(vars, vars.flag("format").unwrap_or("html"))
 }
 
 fn code_coverage(vars: &Vars, format: &str) {
-    let build_type = "debug";
     let target_dir = vars.target_dir.join(format!("coverage-{format}"));
+    let profraw_dir = target_dir.join("profraw");
 
     if target_dir.is_dir() {
         std::fs::remove_dir_all(&target_dir)
             .unwrap_or_else(|e| panic!("Failed to delete {target_dir:?}: {e}"));
     }
+    std::fs::create_dir_all(&profraw_dir)
+        .unwrap_or_else(|e| panic!("Failed to create {profraw_dir:?}: {e}"));
 
-    let ret = Command::new("cargo")
-        .env("CARGO_TARGET_DIR", &target_dir)
-        .env("CARGO_INCREMENTAL", "0")
-        .env("RUSTFLAGS", "-Cinstrument-coverage")
-        .env(
-            "LLVM_PROFILE_FILE",
-            target_dir.join("cargo-test-%p-%m.profraw"),
-        )
-        .arg("test")
-        .arg("--workspace")
-        .args(match build_type {
-            "release" => vec!["--release"],
-            "debug" => vec![],
-            _ => unreachable!("{build_type:?}"),
-        })
+    let tools_dir = llvm_tools_dir();
+
+    // Run the instrumented suite several times; every process drops a
+    // `*.profraw` file into `profraw_dir` and they all accumulate into the one
+    // merged profile below. We cover both the default and all-features sets —
+    // and since each feature set builds a *different* set of instrumented
+    // binaries, we collect the executables from every pass so all of them are
+    // handed to `llvm-cov` as `-object` (otherwise all-features-only code is
+    // unattributed and llvm-cov warns about function-hash mismatches).
+    let mut binaries: Vec<PathBuf> = Vec::new();
+    for features in [&[][..], &["--all-features"][..]] {
+        for bin in build_instrumented_tests(vars, &target_dir, features) {
+            if !binaries.contains(&bin) {
+                binaries.push(bin);
+            }
+        }
+        let (sub, mut args): (&str, Vec<&str>) = if vars.nextest {
+            ("nextest", vec!["run", "--workspace"])
+        } else {
+            ("test", vec!["--workspace"])
+        };
+        args.extend_from_slice(features);
+        run_cargo(sub, &args, vars, &mut instrumented_cargo(&target_dir, &profraw_dir));
+    }
+
+    // nextest's `run` skips doctests, so collect those with a dedicated pass to
+    // keep coverage completeness.
+    if vars.nextest {
+        run_cargo(
+            "test",
+            &["--doc", "--workspace", "--all-features"],
+            vars,
+            &mut instrumented_cargo(&target_dir, &profraw_dir),
+        );
+    }
+
+    // Sweep the coverage target's `deps` directories for any instrumented
+    // executables the JSON passes didn't name (e.g. persisted doctest binaries),
+    // so every object that contributed profraw counters is also an `-object`.
+    for bin in glob_test_binaries(&target_dir) {
+        if !binaries.contains(&bin) {
+            binaries.push(bin);
+        }
+    }
+
+    let profdata = merge_profraw(&tools_dir, &profraw_dir, &target_dir);
+    export_coverage(&tools_dir, &profdata, &binaries, format, &target_dir, vars);
+}
+
+/// Merge every `*.profraw` file under `profraw_dir` into a single
+/// `merged.profdata` using the bundled `llvm-profdata`.
+fn merge_profraw(tools_dir: &Path, profraw_dir: &Path, target_dir: &Path) -> PathBuf {
+    let mut raws = Vec::new();
+    for entry in std::fs::read_dir(profraw_dir)
+        .unwrap_or_else(|e| panic!("Failed to read {profraw_dir:?}: {e}"))
+    {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|e| e.to_str()) == Some("profraw") {
+            raws.push(path);
+        }
+    }
+    assert!(!raws.is_empty(), "No .profraw files were produced in {profraw_dir:?}");
+
+    let profdata = target_dir.join("merged.profdata");
+    let ret = Command::new(llvm_tool(tools_dir, "llvm-profdata"))
+        .args(["merge", "-sparse"])
+        .args(&raws)
+        .arg("-o")
+        .arg(&profdata)
         .status()
-        .unwrap_or_else(|e| panic!("Failed to run cargo: {e}"))
+        .unwrap_or_else(|e| panic!("Failed to run llvm-profdata: {e}"))
         .success();
     assert!(ret);
+    profdata
+}
+
+/// Invoke `llvm-cov` to turn the merged profile plus the test binaries into the
+/// requested report, ignoring sources under `target/`, `tests/`, `examples/`
+/// and `benches/`, the cargo home and the standard library; other files
+/// outside `src/` (such as a `build.rs`) are still reported.
+fn export_coverage(
+    tools_dir: &Path,
+    profdata: &Path,
+    binaries: &[PathBuf],
+    format: &str,
+    target_dir: &Path,
+    vars: &Vars,
+) {
+    let llvm_cov = llvm_tool(tools_dir, "llvm-cov");
+    let ignore = r"(^|/)(?:target|tests|examples|benches)/|/\.cargo/|rustc/";
+
+    let mut cmd = Command::new(&llvm_cov);
+    match format {
+        "lcov" => {
+            cmd.args(["export", "--format=lcov"]);
+        }
+        "text" => {
+            cmd.args(["export", "--format=text"]);
+        }
+        "html" => {
+            cmd.arg("show")
+                .arg("--format=html")
+                .arg("--show-line-counts-or-regions")
+                .arg(format!("-output-dir={}", target_dir.join("html").display()));
+        }
+        _ => panic!("Unknown format {format:?}"),
+    }
+    cmd.arg(format!("-instr-profile={}", profdata.display()))
+        .arg(format!("--ignore-filename-regex={ignore}"));
+    // `rustfilt` is optional; without it `llvm-cov` still demangles Rust names
+    // itself, so only wire it up when the binary is present rather than forcing
+    // contributors (and CI, which only has the rustup component) to install it.
+    if rustfilt_available() {
+        cmd.arg("-Xdemangler=rustfilt");
+    }
+    for binary in binaries {
+        cmd.arg("-object").arg(binary);
+    }
 
     match format {
         "html" => {
-            grcov(&target_dir, "html", build_type);
-            grcov(&target_dir, "lcov", build_type);
-
-            let ret = Command::new("genhtml")
-                .args(["-o", target_dir.join("html2").to_str().unwrap()])
-                .args(["--show-details"])
-                .args(["--highlight"])
-                .args(["--ignore-errors", "source"])
-                .args(["--legend", target_dir.join("lcov").to_str().unwrap()])
+            let ret = cmd
                 .status()
-                .unwrap_or_else(|e| panic!("Failed to run genhtml: {e}"))
+                .unwrap_or_else(|e| panic!("Failed to run llvm-cov: {e}"))
                 .success();
             assert!(ret);
-
             println!("Now open:");
             println!(
                 "  file://{}/html/index.html",
-                vars.cwd.join(&target_dir).display()
-            );
-            println!(
-                "  file://{}/html2/index.html",
-                vars.cwd.join(&target_dir).display()
+                vars.cwd.join(target_dir).display()
             );
         }
-        "lcov" => {
-            grcov(&target_dir, "lcov", build_type);
+        _ => {
+            let report = target_dir.join(format!("coverage.{format}"));
+            let out = cmd
+                .output()
+                .unwrap_or_else(|e| panic!("Failed to run llvm-cov: {e}"));
+            assert!(out.status.success(), "llvm-cov export failed");
+            std::fs::write(&report, out.stdout)
+                .unwrap_or_else(|e| panic!("Failed to write {report:?}: {e}"));
+            println!("Wrote {}", vars.cwd.join(&report).display());
         }
-        _ => panic!("Unknown format {format:?}"),
     }
 }
 
+/// An owned, resolved task step.
+///
+/// [`Action`] describes the compiled-in defaults; `Step` is the runtime form
+/// those are lowered to so that steps loaded from `xtask.toml` (which can only
+/// produce `cargo`/`run`/`sh` entries, never a native [`Action::Call`]) live in
+/// the same table.
+#[derive(Clone)]
+enum Step {
+    Cargo(String, Vec<String>),
+    Run(String),
+    Sh(String),
+    Call(&'static (dyn Fn(&Vars) + Sync)),
+}
+
+/// The built-in [`ACTIONS`] lowered into the owned [`Step`] table that
+/// `xtask.toml` entries are merged on top of.
+fn builtin_tasks() -> Vec<(String, Vec<Step>)> {
+    ACTIONS
+        .iter()
+        .map(|(name, _desc, _flags, actions)| {
+            let steps = actions
+                .iter()
+                .map(|action| match *action {
+                    Action::Cargo(cmd, args) => {
+                        Step::Cargo(cmd.to_owned(), args.iter().map(|&a| a.to_owned()).collect())
+                    }
+                    Action::Call(clb) => Step::Call(clb),
+                    Action::Run(name) => Step::Run(name.to_owned()),
+                })
+                .collect();
+            (name.to_string(), steps)
+        })
+        .collect()
+}
+
+/// Load the workspace `xtask.toml` (if present) and merge its tasks onto
+/// `tasks`, mirroring how cargo resolves aliases: a task whose name already
+/// exists is overridden, new names are appended.
+fn merge_config_tasks(tasks: &mut Vec<(String, Vec<Step>)>, cwd: &Path) {
+    let path = cwd.join("xtask.toml");
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => panic!("Failed to read {path:?}: {e}"),
+    };
+
+    for (name, raw_steps) in parse_xtask_toml(&text) {
+        let steps = raw_steps.iter().map(|s| parse_step(s)).collect();
+        if let Some(entry) = tasks.iter_mut().find(|(n, _)| *n == name) {
+            entry.1 = steps;
+        } else {
+            tasks.push((name, steps));
+        }
+    }
+}
+
+/// Parse a single `xtask.toml` step string of the form `cargo <cmd> <args>`,
+/// `run <other-task>` or `sh <command>`.
+fn parse_step(raw: &str) -> Step {
+    let raw = raw.trim();
+    if let Some(rest) = raw.strip_prefix("run ") {
+        Step::Run(rest.trim().to_owned())
+    } else if let Some(rest) = raw.strip_prefix("sh ") {
+        Step::Sh(rest.trim().to_owned())
+    } else if let Some(rest) = raw.strip_prefix("cargo ") {
+        let mut parts = rest.split_whitespace();
+        let cmd = parts
+            .next()
+            .unwrap_or_else(|| panic!("Missing cargo subcommand in step {raw:?}"));
+        Step::Cargo(cmd.to_owned(), parts.map(str::to_owned).collect())
+    } else {
+        panic!("Invalid step {raw:?}, expected a 'cargo', 'run' or 'sh' prefix");
+    }
+}
+
+/// Parse the `[tasks]` table of an `xtask.toml`, returning each task name with
+/// its ordered list of raw step strings.
+///
+/// Only the small subset we need is understood: a `[tasks]` table mapping bare
+/// (or quoted) keys to arrays of double-quoted strings, with `#` comments.
+fn parse_xtask_toml(text: &str) -> Vec<(String, Vec<String>)> {
+    let mut out = Vec::new();
+    let mut in_tasks = false;
+    let mut lines = text.lines();
+
+    while let Some(raw) = lines.next() {
+        let line = strip_comment(raw);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            in_tasks = line == "[tasks]";
+            continue;
+        }
+        if !in_tasks {
+            continue;
+        }
+
+        let (key, first) = line
+            .split_once('=')
+            .unwrap_or_else(|| panic!("Invalid line in [tasks]: {line:?}"));
+        let key = key.trim().trim_matches('"').to_owned();
+
+        // An array value may span several lines; accumulate until it closes.
+        let mut buf = first.to_owned();
+        while buf.matches('[').count() > buf.matches(']').count() {
+            let next = lines
+                .next()
+                .unwrap_or_else(|| panic!("Unterminated array for task {key:?}"));
+            buf.push('\n');
+            buf.push_str(&strip_comment(next));
+        }
+        out.push((key, parse_string_array(&buf)));
+    }
+    out
+}
+
+/// Drop an end-of-line `#` comment, ignoring `#` characters inside double
+/// quotes so a step like `sh echo "# not a comment"` is preserved.
+fn strip_comment(line: &str) -> String {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut out = String::new();
+    for c in line.chars() {
+        match c {
+            '#' if !in_string => break,
+            '"' if !escaped => {
+                in_string = !in_string;
+                out.push(c);
+            }
+            '\\' if in_string && !escaped => {
+                escaped = true;
+                out.push(c);
+                continue;
+            }
+            _ => out.push(c),
+        }
+        escaped = false;
+    }
+    out
+}
+
+/// Pull the double-quoted strings out of an inline TOML array, honouring `\"`
+/// and `\\` escapes.
+fn parse_string_array(buf: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut chars = buf.chars();
+    while let Some(c) = chars.next() {
+        if c != '"' {
+            continue;
+        }
+        let mut item = String::new();
+        // Drive `chars` by hand so the escape branch can pull the next char
+        // without holding a `by_ref()` borrow across the inner `next()`.
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    if let Some(next) = chars.next() {
+                        item.push(next);
+                    }
+                }
+                '"' => break,
+                _ => item.push(c),
+            }
+        }
+        out.push(item);
+    }
+    out
+}
+
+fn sh_cmd(command: &str, vars: &Vars) {
+    if vars.verbose {
+        println!("Running sh -c {command:?}");
+    }
+    let ret = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run sh: {e}"))
+        .success();
+    assert!(ret);
+}
+
+/// Generate an lcov report and fail the build when lines changed since a base
+/// git ref are not covered.
+///
+/// The base ref and the minimum percentage of changed lines that must be
+/// covered are taken from the trailing `-- <base> <threshold>` arguments,
+/// defaulting to `HEAD` and `80`.
+fn run_diff_coverage(vars: &Vars) {
+    let base = vars.extra_args.first().map(String::as_str).unwrap_or("HEAD");
+    let threshold: f64 = vars
+        .extra_args
+        .get(1)
+        .map(|s| {
+            s.parse()
+                .unwrap_or_else(|e| panic!("Invalid threshold {s:?}: {e}"))
+        })
+        .unwrap_or(80.0);
+
+    code_coverage(vars, "lcov");
+    let lcov_path = vars
+        .target_dir
+        .join("coverage-lcov")
+        .join("coverage.lcov");
+    let lcov = std::fs::read_to_string(&lcov_path)
+        .unwrap_or_else(|e| panic!("Failed to read {lcov_path:?}: {e}"));
+    let coverage = parse_lcov(&lcov);
+    let changed = git_changed_lines(base);
+
+    let mut covered = 0u64;
+    let mut total = 0u64;
+    for (file, lines) in &changed {
+        // lcov source paths are absolute; match the repo-relative diff path by
+        // suffix so either form lines up.
+        let Some((_, file_cov)) = coverage
+            .iter()
+            .find(|(sf, _)| sf.ends_with(file.as_str()))
+        else {
+            continue;
+        };
+
+        let mut uncovered = Vec::new();
+        for line in lines {
+            // Only count lines that are instrumented (have a `DA` entry);
+            // blank lines and declarations carry no coverage counter.
+            if let Some(&count) = file_cov.get(line) {
+                total += 1;
+                if count > 0 {
+                    covered += 1;
+                } else {
+                    uncovered.push(*line);
+                }
+            }
+        }
+        if !uncovered.is_empty() {
+            let list = uncovered
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!("{file}: uncovered changed lines: {list}");
+        }
+    }
+
+    let percent = if total == 0 {
+        100.0
+    } else {
+        covered as f64 / total as f64 * 100.0
+    };
+    println!("Diff coverage vs {base}: {covered}/{total} changed lines covered ({percent:.2}%), threshold {threshold:.2}%");
+
+    if percent + f64::EPSILON < threshold {
+        eprintln!("Diff coverage {percent:.2}% is below the {threshold:.2}% threshold");
+        std::process::exit(1);
+    }
+}
+
+/// Parse an lcov tracefile into `source path -> { line -> hit count }`.
+fn parse_lcov(text: &str) -> BTreeMap<String, BTreeMap<u32, u64>> {
+    let mut map: BTreeMap<String, BTreeMap<u32, u64>> = BTreeMap::new();
+    let mut current: Option<String> = None;
+    for line in text.lines() {
+        if let Some(path) = line.strip_prefix("SF:") {
+            current = Some(path.to_owned());
+            map.entry(path.to_owned()).or_default();
+        } else if let Some(da) = line.strip_prefix("DA:") {
+            // `DA:<line>,<count>[,<checksum>]`
+            let mut fields = da.split(',');
+            if let (Some(ln), Some(count), Some(file)) =
+                (fields.next(), fields.next(), current.as_ref())
+            {
+                if let (Ok(ln), Ok(count)) = (ln.parse::<u32>(), count.parse::<u64>()) {
+                    map.get_mut(file).unwrap().insert(ln, count);
+                }
+            }
+        } else if line == "end_of_record" {
+            current = None;
+        }
+    }
+    map
+}
+
+/// Return the added/modified lines on the new side of `git diff --unified=0
+/// <base>`, grouped by repo-relative file path.
+fn git_changed_lines(base: &str) -> BTreeMap<String, Vec<u32>> {
+    let out = Command::new("git")
+        .args(["diff", "--unified=0", base, "--"])
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to run git diff: {e}"));
+    assert!(out.status.success(), "git diff {base} failed");
+    let diff = String::from_utf8(out.stdout).unwrap();
+
+    let mut changed: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let mut file: Option<String> = None;
+    for line in diff.lines() {
+        if let Some(path) = line.strip_prefix("+++ ") {
+            file = match path {
+                "/dev/null" => None,
+                _ => Some(path.strip_prefix("b/").unwrap_or(path).to_owned()),
+            };
+        } else if let Some(hunk) = line.strip_prefix("@@") {
+            let Some(file) = &file else { continue };
+            // The `+<start>[,<count>]` token describes the new-side range.
+            let Some(plus) = hunk.split_whitespace().find(|t| t.starts_with('+')) else {
+                continue;
+            };
+            let plus = &plus[1..];
+            let (start, count) = match plus.split_once(',') {
+                Some((s, c)) => (s, c.parse::<u32>().unwrap_or(1)),
+                None => (plus, 1),
+            };
+            let start: u32 = start.parse().unwrap_or(0);
+            changed
+                .entry(file.clone())
+                .or_default()
+                .extend(start..start + count);
+        }
+    }
+    changed
+}
+
 fn cargo_cmd(command: &str, args: &[&str], vars: &Vars) {
+    // Only `cargo test`/`cargo clippy`/`cargo nextest run` accept a forwarded
+    // trailing argument list; other subcommands would choke on an unexpected
+    // `--`.
+    let forward = !vars.extra_args.is_empty() && matches!(command, "test" | "clippy" | "nextest");
+
     print!("Running cargo {command}");
     for arg in args.iter() {
         print!(" {arg}");
     }
+    if forward {
+        print!(" --");
+        for arg in vars.extra_args.iter() {
+            print!(" {arg}");
+        }
+    }
     println!();
 
-    let ret = Command::new("cargo")
-        .args(vars.verbose_arg())
-        .arg(command)
-        .args(args)
+    let mut cmd = Command::new("cargo");
+    cmd.args(vars.verbose_arg()).arg(command).args(args);
+    if forward {
+        cmd.arg("--").args(&vars.extra_args);
+    }
+    let ret = cmd
         .status()
         .unwrap_or_else(|e| panic!("Failed to run cargo: {e}"))
         .success();
     assert!(ret);
 }
 
+fn print_help() {
+    println!("Usage: cargo xtask [OPTIONS] <TASK>...");
+    println!();
+    println!("Options:");
+    println!("  -v, --verbose   Pass --verbose to cargo");
+    println!("      --release   Build and test in release profile");
+    println!("      --nextest   Run tests with cargo nextest (auto-detected)");
+    println!("  -h, --help      Print this help");
+    println!("      -- <ARGS>   Forward the remaining arguments to cargo test/clippy");
+    println!();
+    println!("Tasks:");
+    let width = ACTIONS.iter().map(|(t, ..)| t.len()).max().unwrap_or(0);
+    for (name, description, flags, _) in ACTIONS {
+        println!("  {name:width$}  {description}");
+        for flag in *flags {
+            let value = if flag.takes_value { " <VALUE>" } else { "" };
+            let label = format!("--{}{value}", flag.name);
+            println!("  {:width$}    {label}  {}", "", flag.help);
+        }
+    }
+}
+
 pub fn run_xtask() {
     let mut vars = Vars {
         cwd: std::env::current_dir().unwrap().canonicalize().unwrap(),
@@ -191,47 +889,103 @@ pub fn run_xtask() {
             .canonicalize()
             .unwrap(),
         verbose: false,
+        release: false,
+        extra_args: Vec::new(),
+        nextest: false,
+        task_flags: BTreeMap::new(),
     };
 
-    let mut tasks = VecDeque::new();
+    let mut defined = builtin_tasks();
+    merge_config_tasks(&mut defined, &vars.cwd);
 
-    for arg in std::env::args().skip(1) {
-        if arg == "--verbose" || arg == "-v" {
-            vars.verbose = true;
-        } else if let Some((_, actions)) = ACTIONS.iter().find(|&&(t, _)| arg == t) {
-            tasks.extend(actions.iter());
-        } else {
-            eprintln!("Invalid argument {arg:?}");
-            std::process::exit(1);
+    let mut tasks: VecDeque<Step> = VecDeque::new();
+    let mut args = std::env::args().skip(1);
+    // The flags declared by the most recently named task; a `--flag` only parses
+    // if the preceding task accepts it.
+    let mut current_flags: &[TaskFlag] = &[];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--verbose" | "-v" => vars.verbose = true,
+            "--release" => vars.release = true,
+            "--nextest" => vars.nextest = true,
+            "--help" | "-h" => {
+                print_help();
+                return;
+            }
+            // Everything after a bare `--` is forwarded verbatim.
+            "--" => {
+                vars.extra_args.extend(args.by_ref());
+                break;
+            }
+            // A `--flag[=value]` declared by the task named just before it.
+            _ if arg.starts_with("--") => {
+                let (name, inline) = match arg[2..].split_once('=') {
+                    Some((n, v)) => (n, Some(v.to_owned())),
+                    None => (&arg[2..], None),
+                };
+                let Some(flag) = current_flags.iter().find(|f| f.name == name) else {
+                    eprintln!("Unknown option {arg:?}");
+                    std::process::exit(1);
+                };
+                let value = if flag.takes_value {
+                    Some(inline.unwrap_or_else(|| {
+                        args.next().unwrap_or_else(|| {
+                            eprintln!("Option --{name} requires a value");
+                            std::process::exit(1);
+                        })
+                    }))
+                } else {
+                    None
+                };
+                vars.task_flags.insert(name.to_owned(), value);
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option {arg:?}");
+                std::process::exit(1);
+            }
+            _ => {
+                if let Some((_, steps)) = defined.iter().find(|(t, _)| *t == arg) {
+                    tasks.extend(steps.iter().cloned());
+                    current_flags = task_flags(&arg);
+                } else {
+                    eprintln!("Invalid argument {arg:?}");
+                    std::process::exit(1);
+                }
+            }
         }
     }
 
     if tasks.is_empty() {
-        eprint!("Missing action, use one of ");
-        for (i, &(t, _)) in ACTIONS.iter().enumerate() {
-            if i != 0 {
-                eprint!(", {t}");
-            } else {
-                eprint!("{t}");
-            }
-        }
-        eprintln!();
+        eprintln!("Missing action, run with --help to list the built-in tasks");
         std::process::exit(1);
     }
 
-    while let Some(action) = tasks.pop_front() {
-        match *action {
-            Action::Cargo(cmd, args) => cargo_cmd(cmd, args, &vars),
-            Action::Call(clb) => clb(&vars),
-            Action::Run(name) => {
-                tasks.extend(
-                    ACTIONS
-                        .iter()
-                        .find(|&&(t, _)| name == t)
-                        .unwrap_or_else(|| panic!("Unknown action {name}"))
-                        .1
-                        .iter(),
-                );
+    // Auto-detect nextest unless it was explicitly requested on the CLI.
+    if !vars.nextest {
+        vars.nextest = nextest_available();
+    }
+
+    while let Some(step) = tasks.pop_front() {
+        match step {
+            Step::Cargo(cmd, args) => {
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                cargo_cmd(&cmd, &args, &vars);
+            }
+            Step::Sh(command) => sh_cmd(&command, &vars),
+            Step::Call(clb) => clb(&vars),
+            Step::Run(name) => {
+                let steps = defined
+                    .iter()
+                    .find(|(t, _)| *t == name)
+                    .unwrap_or_else(|| panic!("Unknown action {name}"))
+                    .1
+                    .clone();
+                // Preserve ordering: the referenced task's steps run before the
+                // remaining queued steps.
+                for step in steps.into_iter().rev() {
+                    tasks.push_front(step);
+                }
             }
         }
     }